@@ -13,34 +13,427 @@ use rsa::{
     pkcs1::DecodeRsaPrivateKey,
     pkcs8::{DecodePrivateKey, EncodePublicKey, LineEnding},
 };
+use rsa::traits::PublicKeyParts;
 use serde::{Deserialize, Serialize};
-use sha2::Sha256;
-use std::{env, fs, io::Write, net::SocketAddr, process::Command, sync::Arc};
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, env, fs, net::SocketAddr, path::Path, sync::Arc};
 use thiserror::Error;
-use time::macros::format_description;
 use time::{Duration, OffsetDateTime};
 use tracing::{error, info};
 use tracing_subscriber::EnvFilter;
 
 // base64 新 API
-use base64::{Engine as _, engine::general_purpose::STANDARD as B64};
+use base64::{
+    Engine as _,
+    engine::general_purpose::{STANDARD as B64, URL_SAFE_NO_PAD as B64URL},
+};
 
-// 颁发 SSH 证书所需
+// 颁发 SSH 证书所需：进程内签名，无需 ssh-keygen 二进制
 use rand::{RngCore, rngs::OsRng};
-use tempfile::NamedTempFile;
+use ssh_key::{PrivateKey as SshPrivateKey, PublicKey as SshPublicKey, certificate};
+
+// ES256 公钥坐标提取所需
+use p256::elliptic_curve::sec1::ToEncodedPoint;
 
-/// 应用全局状态：JWT 用 HS256，对称密钥；RSA 仅用于传输层解密与导出公钥
+/// 应用全局状态：JWT 签名密钥（对称或非对称）；RSA 仅用于传输层解密与导出公钥
 #[derive(Clone)]
 struct AppState {
-    // HS256 对称签名密钥
-    jwt_key: Arc<EncodingKey>,
-    // RSA 私钥（仅用于 OAEP 解密 & 导出公钥）
-    rsa_private: Arc<RsaPrivateKey>,
+    // JWT 签名密钥集合，按 created_at 升序排列；支持重叠有效期的零停机轮换
+    signing_keys: Arc<Vec<SigningKeyEntry>>,
+    // RSA 传输密钥集合（仅用于 OAEP 解密 & 导出公钥），同样按 created_at 升序排列
+    rsa_keys: Arc<Vec<RsaKeyEntry>>,
 
     jwt_exp_minutes: i64,
     pam_service: String,
     // 抗重放窗口（秒）
     max_payload_age_secs: i64,
+    // Redis nonce 存储，用于严格抗重放；未设置 REDIS_URL 时为 None，退化为仅校验时间戳
+    redis: Option<redis::aio::ConnectionManager>,
+    // 按用户/组授予 SSH 证书权限的策略；未配置时为空策略（无额外 principal/扩展/关键选项）
+    cert_policy: Arc<CertPolicy>,
+}
+
+impl AppState {
+    /// 当前用于签名的密钥：按 `created_at` 排序后最新的一个未退役的密钥
+    fn active_signing_key(&self) -> Result<&SigningKeyEntry, ApiError> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        self.signing_keys
+            .iter()
+            .rev()
+            .find(|entry| entry.not_after.is_none_or(|na| na > now))
+            .ok_or(ApiError::Internal("no active signing key"))
+    }
+}
+
+/// 密钥清单中的一条记录（JSON 清单文件，通过 env 指定路径）
+#[derive(Deserialize)]
+struct KeyManifestEntry {
+    id: String,
+    path: String,
+    /// 密钥算法（HS256/RS256/ES256）；仅 JWT 签名密钥清单需要，RSA 传输密钥清单忽略
+    alg: Option<String>,
+    /// 退役时间（unix 秒）；到期后不再用于签名或对外发布，但仍保留用于过渡期解密
+    not_after: Option<i64>,
+    /// 密钥生成时间（unix 秒）；决定"最新"密钥，必须由生成清单的流程显式给出，
+    /// 不依赖清单数组的书写顺序（追加顺序无法保证，生成脚本也可能乱序）
+    created_at: i64,
+}
+
+#[derive(Deserialize)]
+struct KeyManifest {
+    keys: Vec<KeyManifestEntry>,
+}
+
+fn read_key_manifest(path: &str) -> Result<KeyManifest, Box<dyn std::error::Error>> {
+    let raw = fs::read_to_string(path).map_err(|e| {
+        error!("failed to read key manifest {}: {}", path, e);
+        e
+    })?;
+    Ok(serde_json::from_str(&raw)?)
+}
+
+/// 一把已加载的 JWT 签名密钥，附带其清单元数据
+struct SigningKeyEntry {
+    id: String,
+    key: SigningKey,
+    not_after: Option<i64>,
+    created_at: i64,
+}
+
+/// 一把已加载的 RSA 传输密钥，附带其清单元数据
+struct RsaKeyEntry {
+    id: String,
+    private: RsaPrivateKey,
+    not_after: Option<i64>,
+    created_at: i64,
+}
+
+/// 非对称签名密钥的签名材料 + 对外发布的 JWK 表示
+struct AsymmetricKey {
+    encoding_key: EncodingKey,
+    kid: String,
+    jwk: serde_json::Value,
+}
+
+/// JWT 签名密钥：HS256 用共享密钥；RS256/ES256 携带 `kid`，公钥通过 JWKS 发布
+enum SigningKey {
+    Hmac(EncodingKey),
+    Rsa(AsymmetricKey),
+    Ec(AsymmetricKey),
+}
+
+impl SigningKey {
+    fn algorithm(&self) -> Algorithm {
+        match self {
+            SigningKey::Hmac(_) => Algorithm::HS256,
+            SigningKey::Rsa(_) => Algorithm::RS256,
+            SigningKey::Ec(_) => Algorithm::ES256,
+        }
+    }
+
+    fn encoding_key(&self) -> &EncodingKey {
+        match self {
+            SigningKey::Hmac(k) => k,
+            SigningKey::Rsa(k) => &k.encoding_key,
+            SigningKey::Ec(k) => &k.encoding_key,
+        }
+    }
+
+    fn kid(&self) -> Option<&str> {
+        match self {
+            SigningKey::Hmac(_) => None,
+            SigningKey::Rsa(k) => Some(&k.kid),
+            SigningKey::Ec(k) => Some(&k.kid),
+        }
+    }
+
+    /// 用于 `/.well-known/jwks.json`；HS256 没有可发布的公钥
+    fn jwk(&self) -> Option<&serde_json::Value> {
+        match self {
+            SigningKey::Hmac(_) => None,
+            SigningKey::Rsa(k) => Some(&k.jwk),
+            SigningKey::Ec(k) => Some(&k.jwk),
+        }
+    }
+}
+
+/// 由公钥材料派生一个短小稳定的 `kid`（sha256 前 16 字节，base64url）
+fn derive_kid(public_key_bytes: &[u8]) -> String {
+    let digest = Sha256::digest(public_key_bytes);
+    B64URL.encode(&digest[..16])
+}
+
+/// 构造 RS256 JWK（RFC 7518 3.3 节字段）
+fn rsa_jwk(public: &rsa::RsaPublicKey, kid: &str) -> serde_json::Value {
+    serde_json::json!({
+        "kty": "RSA",
+        "use": "sig",
+        "alg": "RS256",
+        "kid": kid,
+        "n": B64URL.encode(public.n().to_bytes_be()),
+        "e": B64URL.encode(public.e().to_bytes_be()),
+    })
+}
+
+/// 构造 ES256 JWK（P-256，RFC 7518 6.2 节字段）
+fn ec_jwk(point: &p256::EncodedPoint, kid: &str) -> serde_json::Value {
+    serde_json::json!({
+        "kty": "EC",
+        "crv": "P-256",
+        "use": "sig",
+        "alg": "ES256",
+        "kid": kid,
+        "x": B64URL.encode(point.x().expect("uncompressed point has x")),
+        "y": B64URL.encode(point.y().expect("uncompressed point has y")),
+    })
+}
+
+/// 按 `alg`（HS256/RS256/ES256）从 `path` 加载一把签名密钥
+fn load_signing_key(alg: &str, path: &str) -> Result<SigningKey, Box<dyn std::error::Error>> {
+    match alg {
+        "HS256" => {
+            let secret_bytes = fs::read(path).map_err(|e| {
+                error!("failed to read key file {}: {}", path, e);
+                e
+            })?;
+            if secret_bytes.len() < 32 {
+                error!("JWT secret seems too short; please use at least 32 random bytes for HS256");
+            }
+            Ok(SigningKey::Hmac(EncodingKey::from_secret(&secret_bytes)))
+        }
+        "RS256" => {
+            let pem = fs::read_to_string(path).map_err(|e| {
+                error!("failed to read JWT signing key {}: {}", path, e);
+                e
+            })?;
+            // 复用现有 PKCS#8/PKCS#1 加载逻辑
+            let rsa_key = RsaPrivateKey::from_pkcs8_pem(&pem)
+                .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))
+                .map_err(|e| {
+                    error!("failed to parse RS256 signing key: {}", e);
+                    e
+                })?;
+            let encoding_key = EncodingKey::from_rsa_pem(pem.as_bytes())?;
+            let public = rsa_key.to_public_key();
+            let kid = derive_kid(&public.n().to_bytes_be());
+            let jwk = rsa_jwk(&public, &kid);
+            Ok(SigningKey::Rsa(AsymmetricKey {
+                encoding_key,
+                kid,
+                jwk,
+            }))
+        }
+        "ES256" => {
+            let pem = fs::read_to_string(path).map_err(|e| {
+                error!("failed to read JWT signing key {}: {}", path, e);
+                e
+            })?;
+            let secret = p256::SecretKey::from_pkcs8_pem(&pem)
+                .or_else(|_| p256::SecretKey::from_sec1_pem(&pem))
+                .map_err(|e| {
+                    error!("failed to parse ES256 signing key: {}", e);
+                    e
+                })?;
+            let encoding_key = EncodingKey::from_ec_pem(pem.as_bytes())?;
+            let point = secret.public_key().to_encoded_point(false);
+            let kid = derive_kid(point.as_bytes());
+            let jwk = ec_jwk(&point, &kid);
+            Ok(SigningKey::Ec(AsymmetricKey {
+                encoding_key,
+                kid,
+                jwk,
+            }))
+        }
+        other => Err(format!("unsupported JWT_ALG: {other} (expected HS256, RS256 or ES256)").into()),
+    }
+}
+
+/// 加载 JWT 签名密钥集：设置 `JWT_KEYSET_PATH` 时从清单加载多把密钥（每条记录须带
+/// `alg`，如 `"RS256"`，以及 `created_at`），否则退化为单把密钥（沿用 `JWT_ALG`/
+/// `JWT_KEY_PATH`/`JWT_SIGNING_KEY_PATH`），保持旧部署零改动
+fn load_signing_keyset() -> Result<Vec<SigningKeyEntry>, Box<dyn std::error::Error>> {
+    if let Ok(manifest_path) = env::var("JWT_KEYSET_PATH") {
+        let manifest = read_key_manifest(&manifest_path)?;
+        let mut entries: Vec<SigningKeyEntry> = manifest
+            .keys
+            .into_iter()
+            .map(|entry| {
+                let alg = entry
+                    .alg
+                    .clone()
+                    .ok_or_else(|| format!("key manifest entry {} is missing alg", entry.id))?;
+                let key = load_signing_key(&alg, &entry.path)?;
+                info!("loaded JWT signing key '{}' ({})", entry.id, alg);
+                Ok(SigningKeyEntry {
+                    id: entry.id,
+                    key,
+                    not_after: entry.not_after,
+                    created_at: entry.created_at,
+                })
+            })
+            .collect::<Result<_, Box<dyn std::error::Error>>>()?;
+        // 按 created_at 升序排列：哪把密钥"最新"由此决定，而不是清单数组的书写顺序
+        entries.sort_by_key(|e| e.created_at);
+        return Ok(entries);
+    }
+
+    let alg = env::var("JWT_ALG").unwrap_or_else(|_| "HS256".into());
+    let path = match alg.as_str() {
+        "HS256" => env::var("JWT_KEY_PATH").unwrap_or_else(|_| "jwt_hs256.key".into()),
+        _ => env::var("JWT_SIGNING_KEY_PATH")
+            .unwrap_or_else(|_| format!("jwt_{}.key", alg.to_lowercase())),
+    };
+    let key = load_signing_key(&alg, &path)?;
+    Ok(vec![SigningKeyEntry {
+        id: alg,
+        key,
+        not_after: None,
+        created_at: 0,
+    }])
+}
+
+/// 加载一把 RSA 传输私钥（PKCS#8，失败回退 PKCS#1）
+fn load_rsa_key(path: &str) -> Result<RsaPrivateKey, Box<dyn std::error::Error>> {
+    let pem = fs::read_to_string(path).map_err(|e| {
+        error!("failed to read RSA key {}: {}", path, e);
+        e
+    })?;
+    RsaPrivateKey::from_pkcs8_pem(&pem)
+        .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))
+        .map_err(|e| {
+            error!("failed to parse RSA private key: {}", e);
+            e.into()
+        })
+}
+
+/// 加载 RSA 传输密钥集：设置 `RSA_KEYSET_PATH` 时从清单加载多把密钥（每条记录须带
+/// `created_at`），否则退化为单把密钥（沿用 `RSA_PRIVATE_KEY_PATH`），保持旧部署零改动
+fn load_rsa_keyset() -> Result<Vec<RsaKeyEntry>, Box<dyn std::error::Error>> {
+    if let Ok(manifest_path) = env::var("RSA_KEYSET_PATH") {
+        let manifest = read_key_manifest(&manifest_path)?;
+        let mut entries: Vec<RsaKeyEntry> = manifest
+            .keys
+            .into_iter()
+            .map(|entry| {
+                let private = load_rsa_key(&entry.path)?;
+                info!("loaded RSA transport key '{}'", entry.id);
+                Ok(RsaKeyEntry {
+                    id: entry.id,
+                    private,
+                    not_after: entry.not_after,
+                    created_at: entry.created_at,
+                })
+            })
+            .collect::<Result<_, Box<dyn std::error::Error>>>()?;
+        // 按 created_at 升序排列：哪把密钥"最新"由此决定，而不是清单数组的书写顺序
+        entries.sort_by_key(|e| e.created_at);
+        return Ok(entries);
+    }
+
+    let path = env::var("RSA_PRIVATE_KEY_PATH").unwrap_or_else(|_| "rsa_private.pem".into());
+    let private = load_rsa_key(&path)?;
+    Ok(vec![RsaKeyEntry {
+        id: "default".into(),
+        private,
+        not_after: None,
+        created_at: 0,
+    }])
+}
+
+/// 一条 SSH 证书规则：额外 principal、扩展、关键选项，以及证书有效期的上限
+#[derive(Deserialize, Default, Clone)]
+struct CertRule {
+    #[serde(default)]
+    principals: Vec<String>,
+    #[serde(default)]
+    extensions: Vec<String>,
+    #[serde(default)]
+    critical_options: BTreeMap<String, String>,
+    /// 证书最大有效期（秒），独立于 jwt_exp_minutes 对证书寿命做钳制
+    max_validity_secs: Option<i64>,
+}
+
+impl CertRule {
+    /// 合并另一条规则：principal/扩展取并集，关键选项合并，有效期取二者中更严格（更短）的一个
+    fn merge(&mut self, other: &CertRule) {
+        for p in &other.principals {
+            if !self.principals.contains(p) {
+                self.principals.push(p.clone());
+            }
+        }
+        for e in &other.extensions {
+            if !self.extensions.contains(e) {
+                self.extensions.push(e.clone());
+            }
+        }
+        for (k, v) in &other.critical_options {
+            self.critical_options.insert(k.clone(), v.clone());
+        }
+        self.max_validity_secs = match (self.max_validity_secs, other.max_validity_secs) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, None) => a,
+            (None, b) => b,
+        };
+    }
+}
+
+/// SSH 证书签发策略：按用户名或所属组授予额外 principal / 扩展 / 关键选项
+#[derive(Deserialize, Default)]
+struct CertPolicy {
+    #[serde(default)]
+    users: BTreeMap<String, CertRule>,
+    #[serde(default)]
+    groups: BTreeMap<String, CertRule>,
+}
+
+impl CertPolicy {
+    /// 解析某个已通过 PAM 认证的用户应适用的规则：其所属各组的规则按组名字典序
+    /// 先合并（保证与 NSS/getgrouplist 返回顺序无关、结果确定），再叠加用户专属
+    /// 规则（用户规则优先级最高，出现在合并结果末尾）
+    fn resolve(&self, username: &str) -> CertRule {
+        let mut groups = unix_groups_of(username);
+        groups.sort();
+
+        let mut merged = CertRule::default();
+        for group in groups {
+            if let Some(rule) = self.groups.get(&group) {
+                merged.merge(rule);
+            }
+        }
+        if let Some(rule) = self.users.get(username) {
+            merged.merge(rule);
+        }
+        merged
+    }
+}
+
+/// 查询某用户所属的全部组名（含主组与次要组；用于 cert_policy 的按组授权）
+fn unix_groups_of(username: &str) -> Vec<String> {
+    let Some(user) = users::get_user_by_name(username) else {
+        return Vec::new();
+    };
+    users::get_user_groups(username, user.primary_group_id())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|g| g.name().to_string_lossy().into_owned())
+        .collect()
+}
+
+/// 按 `SSH_CERT_POLICY_PATH`（JSON 或 `.toml`）加载证书策略；未设置时返回空策略，
+/// 此时证书仅含 PAM 用户名作为 principal，沿用全局 `SSH_CERT_EXTENSIONS`
+fn load_cert_policy() -> Result<CertPolicy, Box<dyn std::error::Error>> {
+    let Ok(path) = env::var("SSH_CERT_POLICY_PATH") else {
+        return Ok(CertPolicy::default());
+    };
+    let raw = fs::read_to_string(&path).map_err(|e| {
+        error!("failed to read SSH cert policy {}: {}", path, e);
+        e
+    })?;
+    if path.ends_with(".toml") {
+        Ok(toml::from_str(&raw)?)
+    } else {
+        Ok(serde_json::from_str(&raw)?)
+    }
 }
 
 /// /auth/token 请求体（密文，Base64）
@@ -54,7 +447,8 @@ struct EncryptedTokenRequest {
 struct AuthPayload {
     username: String,
     password: String,
-    ts: i64, // unix 秒，用于抗重放
+    ts: i64,     // unix 秒，用于抗重放
+    nonce: String, // 客户端生成的一次性随机串，配合 Redis 做严格抗重放
     ssh_pubkey: Option<String>,
 }
 
@@ -112,87 +506,102 @@ impl IntoResponse for ApiError {
     }
 }
 
-/// 将 OffsetDateTime 格式化为 ssh-keygen 需要的 UTC "YYYYMMDDHHMMSS"
-fn fmt_utc(ts: OffsetDateTime) -> String {
-    // OpenSSH 要求无分隔紧凑格式
-    // 统一转为 UTC
-    let ts_utc = ts.to_offset(time::UtcOffset::UTC);
-    ts_utc
-        .format(&format_description!(
-            "[year][month][day][hour][minute][second]"
-        ))
-        .unwrap()
+/// 已知的 OpenSSH 证书关键选项名（`ssh-keygen -O` 语义）：sshd 只会强制执行写在
+/// critical options 里的选项，写进普通 extensions 会被静默忽略
+const CRITICAL_OPTION_NAMES: &[&str] = &["force-command", "source-address", "verify-required"];
+
+/// 把一个 `-O` 风格 token（`name` 或 `name=value`）分类写入证书：已知的关键选项名
+/// 路由到 critical options（sshd 实际执行的限制），其余原样写入普通 extensions；
+/// `ssh-keygen -O` 的 `no-*`、`clear` 等移除类写法不做解释，同样按扩展名原样写入
+fn apply_cert_option(builder: &mut certificate::Builder, token: &str) -> Result<(), ApiError> {
+    let (name, data) = token.split_once('=').unwrap_or((token, ""));
+    if CRITICAL_OPTION_NAMES.contains(&name) {
+        builder
+            .critical_option(name, data)
+            .map_err(|_| ApiError::Internal("cert critical option"))?;
+    } else {
+        builder
+            .extension(name, data)
+            .map_err(|_| ApiError::Internal("cert extension"))?;
+    }
+    Ok(())
 }
 
-/// 调用 ssh-keygen 使用 CA 私钥签发用户证书，返回证书文本（*-cert.pub 一行）
-fn issue_ssh_user_cert(
-    ca_key_path: &str,
-    principal: &str,
+/// `issue_ssh_user_cert` 的入参：已从 PAM 用户名与 `cert_policy` 解析出的证书内容
+struct SshCertRequest<'a> {
+    ca_key_path: &'a str,
+    principals: &'a [String],
     valid_after: OffsetDateTime,
     valid_before: OffsetDateTime,
-    ssh_pubkey: &str,
-    extensions: &[&str],
+    ssh_pubkey: &'a str,
+    extensions: &'a [&'a str],
+    critical_options: &'a BTreeMap<String, String>,
     serial_base: u64,
-) -> Result<String, ApiError> {
-    // 1) 用户公钥写入临时文件
-    let tmp_dir = env::var("SSH_CERT_TMP_DIR").unwrap_or_else(|_| "/tmp".into());
-    let mut pub_tmp = NamedTempFile::new_in(tmp_dir).map_err(|_| ApiError::Internal("tmpfile"))?;
-    pub_tmp
-        .write_all(ssh_pubkey.as_bytes())
-        .map_err(|_| ApiError::Internal("tmp write"))?;
-    pub_tmp.flush().ok();
-
-    let pub_path = pub_tmp.path().to_owned();
-    let cert_path = pub_path.with_file_name(format!(
-        "{}-cert.pub",
-        pub_path.file_name().unwrap().to_string_lossy()
-    ));
+}
+
+/// 在进程内使用 `ssh-key` 签发 OpenSSH 用户证书，返回证书文本（一行），
+/// 支持 Ed25519/RSA/ECDSA CA 密钥，不再依赖 `ssh-keygen` 二进制或临时文件
+fn issue_ssh_user_cert(req: SshCertRequest) -> Result<String, ApiError> {
+    if req.principals.is_empty() {
+        return Err(ApiError::Internal("no principals for cert"));
+    }
+
+    // 1) 解析用户公钥与 CA 私钥
+    let public_key = SshPublicKey::from_openssh(req.ssh_pubkey)
+        .map_err(|_| ApiError::BadRequest("invalid ssh_pubkey"))?;
+    let ca_key = SshPrivateKey::read_openssh_file(Path::new(req.ca_key_path)).map_err(|e| {
+        error!("failed to read SSH CA key {}: {}", req.ca_key_path, e);
+        ApiError::Internal("ssh ca key")
+    })?;
 
     // 2) 随机序列号（基于 serial_base 做偏移）
     let mut rnd = [0u8; 8];
     OsRng.fill_bytes(&mut rnd);
-    let serial = serial_base.wrapping_add(u64::from_le_bytes(rnd));
-
-    // 3) 组织 ssh-keygen 参数
-    let from = fmt_utc(valid_after);
-    let to = fmt_utc(valid_before);
+    let serial = req.serial_base.wrapping_add(u64::from_le_bytes(rnd));
     let key_id = format!(
         "pam-jwt-issuer:{}:{}",
-        principal,
-        valid_after.unix_timestamp()
+        req.principals[0],
+        req.valid_after.unix_timestamp()
     );
 
-    let mut cmd = Command::new("ssh-keygen");
-    cmd.arg("-s")
-        .arg(ca_key_path)
-        .arg("-I")
-        .arg(&key_id)
-        .arg("-n")
-        .arg(principal)
-        .arg("-V")
-        .arg(format!("{}:{}", from, to))
-        .arg("-z")
-        .arg(serial.to_string())
-        .arg(&pub_path);
-
-    for ext in extensions {
+    // 3) 构造并签发证书
+    let mut builder = certificate::Builder::new_with_random_nonce(
+        &mut OsRng,
+        public_key,
+        req.valid_after.unix_timestamp() as u64,
+        req.valid_before.unix_timestamp() as u64,
+    )
+    .map_err(|_| ApiError::Internal("cert builder"))?;
+    builder
+        .serial(serial)
+        .map_err(|_| ApiError::Internal("cert serial"))?;
+    builder
+        .key_id(key_id)
+        .map_err(|_| ApiError::Internal("cert key id"))?;
+    builder
+        .cert_type(certificate::CertType::User)
+        .map_err(|_| ApiError::Internal("cert type"))?;
+    for principal in req.principals {
+        builder
+            .valid_principal(principal)
+            .map_err(|_| ApiError::Internal("cert principal"))?;
+    }
+    for ext in req.extensions {
         if !ext.is_empty() {
-            cmd.arg("-O").arg(ext);
+            apply_cert_option(&mut builder, ext)?;
         }
     }
-
-    let output = cmd
-        .output()
-        .map_err(|_| ApiError::Internal("ssh-keygen spawn"))?;
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        error!("ssh-keygen failed: {}", stderr);
-        return Err(ApiError::Internal("ssh-keygen failed"));
+    for (name, data) in req.critical_options {
+        builder
+            .critical_option(name, data)
+            .map_err(|_| ApiError::Internal("cert critical option"))?;
     }
 
-    // 4) 读取生成的证书文本
-    let cert_text = fs::read_to_string(&cert_path).map_err(|_| ApiError::Internal("read cert"))?;
-    Ok(cert_text.trim().to_string())
+    let cert = builder.sign(&ca_key).map_err(|e| {
+        error!("failed to sign ssh certificate: {}", e);
+        ApiError::Internal("cert sign")
+    })?;
+    cert.to_openssh().map_err(|_| ApiError::Internal("cert encode"))
 }
 
 #[tokio::main]
@@ -205,40 +614,53 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with_env_filter(EnvFilter::from_default_env())
         .init();
 
-    // 读取 HS256 密钥文件路径
-    let key_path = env::var("JWT_KEY_PATH").unwrap_or_else(|_| "jwt_hs256.key".to_string());
-    let secret_bytes = fs::read(&key_path).map_err(|e| {
-        error!("failed to read key file {}: {}", key_path, e);
-        e
-    })?;
+    // 加载 JWT 签名密钥集：JWT_KEYSET_PATH 指向清单时支持重叠有效期的多把密钥，
+    // 否则退化为 JWT_ALG 指定的单把密钥
+    let signing_keys = load_signing_keyset()?;
 
-    if secret_bytes.len() < 32 {
-        error!("JWT secret seems too short; please use at least 32 random bytes for HS256");
-    }
+    // 加载 RSA 传输密钥集（仅用于 OAEP 解密与导出公钥；与 JWT 签名密钥完全分离）
+    let rsa_keys = load_rsa_keyset()?;
 
-    // 构造 JWT HS256 EncodingKey
-    let jwt_key = EncodingKey::from_secret(&secret_bytes);
+    // 启动即校验：清单里必须至少有一把未退役的签名密钥/RSA 传输密钥，否则立刻失败
+    // 退出，而不是把"/auth/token 全部 500"留给上线后才发现
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let active = signing_keys
+        .iter()
+        .rev()
+        .find(|entry| entry.not_after.is_none_or(|na| na > now))
+        .ok_or_else(|| {
+            error!("no active (non-retired) JWT signing key loaded");
+            "no active (non-retired) JWT signing key"
+        })?;
+    info!("active JWT signing key: '{}' ({:?})", active.id, active.key.algorithm());
 
-    // 读取 RSA 私钥（仅用于 OAEP 解密与导出公钥；与 JWT 密钥完全分离）
-    let rsa_key_path =
-        env::var("RSA_PRIVATE_KEY_PATH").unwrap_or_else(|_| "rsa_private.pem".into());
-    let pem = fs::read_to_string(&rsa_key_path).map_err(|e| {
-        error!("failed to read RSA key {}: {}", rsa_key_path, e);
-        e
-    })?;
+    if !rsa_keys
+        .iter()
+        .any(|entry| entry.not_after.is_none_or(|na| na > now))
+    {
+        error!("no active (non-retired) RSA transport key loaded");
+        return Err("no active (non-retired) RSA transport key".into());
+    }
 
-    // 解析 RSA 私钥：优先 PKCS#8，失败回退 PKCS#1
-    let rsa_private = RsaPrivateKey::from_pkcs8_pem(&pem)
-        .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))
-        .map_err(|e| {
-            error!("failed to parse RSA private key: {}", e);
-            e
-        })?;
+    // 可选 Redis nonce 存储：设置 REDIS_URL 时启用严格抗重放
+    let redis = match env::var("REDIS_URL") {
+        Ok(url) => {
+            let client = redis::Client::open(url)?;
+            Some(client.get_connection_manager().await?)
+        }
+        Err(_) => {
+            info!("REDIS_URL not set; falling back to timestamp-only replay protection");
+            None
+        }
+    };
+
+    // 可选 SSH 证书策略：按用户/组授予额外 principal、扩展与关键选项
+    let cert_policy = load_cert_policy()?;
 
     // 应用状态
     let state = AppState {
-        jwt_key: Arc::new(jwt_key),
-        rsa_private: Arc::new(rsa_private),
+        signing_keys: Arc::new(signing_keys),
+        rsa_keys: Arc::new(rsa_keys),
         jwt_exp_minutes: env::var("JWT_EXPIRE_MINUTES")
             .ok()
             .and_then(|s| s.parse::<i64>().ok())
@@ -248,12 +670,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .ok()
             .and_then(|s| s.parse::<i64>().ok())
             .unwrap_or(60),
+        redis,
+        cert_policy: Arc::new(cert_policy),
     };
 
     // 路由：探活 / 公钥 / 颁发
     let app = Router::new()
         .route("/healthz", get(|| async { "ok" }))
         .route("/pubkey", get(get_pubkey))
+        .route("/.well-known/jwks.json", get(get_jwks))
         .route("/auth/token", post(issue_token))
         .with_state(state);
 
@@ -267,24 +692,40 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-/// 返回 RSA 公钥（PEM），用于客户端进行加密
+/// 返回所有未退役的 RSA 公钥（PEM），按最新在前排列；客户端加密时取第一个即可，
+/// 轮换窗口内仍在用的旧密钥同样列出，因为 `issue_token` 会逐一尝试解密
 async fn get_pubkey(State(state): State<AppState>) -> Result<impl IntoResponse, ApiError> {
-    let pub_pem = state
-        .rsa_private
-        .to_public_key()
-        .to_public_key_pem(LineEnding::LF)
-        .map_err(|_| ApiError::Internal("export public key"))?;
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let mut keys = Vec::new();
+    for entry in state.rsa_keys.iter().rev() {
+        if entry.not_after.is_some_and(|na| na <= now) {
+            continue;
+        }
+        let pem = entry
+            .private
+            .to_public_key()
+            .to_public_key_pem(LineEnding::LF)
+            .map_err(|_| ApiError::Internal("export public key"))?;
+        keys.push(serde_json::json!({ "id": entry.id, "pem": pem }));
+    }
 
-    Ok((
-        StatusCode::OK,
-        (
-            [("Content-Type", "application/x-pem-file")],
-            pub_pem.to_string(),
-        ),
-    ))
+    Ok((StatusCode::OK, Json(serde_json::json!({ "keys": keys }))))
+}
+
+/// 返回 JWKS 文档，供验证方获取所有未退役签名密钥的公钥；HS256 密钥没有可发布的公钥
+async fn get_jwks(State(state): State<AppState>) -> impl IntoResponse {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let keys: Vec<&serde_json::Value> = state
+        .signing_keys
+        .iter()
+        .rev()
+        .filter(|entry| entry.not_after.is_none_or(|na| na > now))
+        .filter_map(|entry| entry.key.jwk())
+        .collect();
+    Json(serde_json::json!({ "keys": keys }))
 }
 
-/// 解密密文 -> PAM 认证 -> HS256 签 JWT
+/// 解密密文 -> PAM 认证 -> 签发 JWT（HS256 或 RS256/ES256）
 async fn issue_token(
     State(state): State<AppState>,
     Json(req): Json<EncryptedTokenRequest>,
@@ -294,12 +735,13 @@ async fn issue_token(
         .decode(&req.ciphertext_b64)
         .map_err(|_| ApiError::BadRequest("invalid base64"))?;
 
-    // 2) RSA-OAEP(SHA-256) 解密
-    let oaep = Oaep::new::<Sha256>();
+    // 2) RSA-OAEP(SHA-256) 解密：依次尝试每把已加载的传输密钥，兼容客户端持有的
+    //    `/pubkey` 响应略过期于当前最新密钥的情况（重叠轮换窗口）
     let plaintext = state
-        .rsa_private
-        .decrypt(oaep, &ciphertext)
-        .map_err(|_| ApiError::BadRequest("invalid ciphertext"))?;
+        .rsa_keys
+        .iter()
+        .find_map(|entry| entry.private.decrypt(Oaep::new::<Sha256>(), &ciphertext).ok())
+        .ok_or(ApiError::BadRequest("invalid ciphertext"))?;
 
     // 3) 解析明文 JSON
     let payload: AuthPayload = serde_json::from_slice(&plaintext)
@@ -317,6 +759,28 @@ async fn issue_token(
         return Err(ApiError::BadRequest("stale or future timestamp"));
     }
 
+    // 4b) 抗重放：若配置了 Redis，原子地 SET nonce NX EX 以拒绝重放密文；
+    //     TTL 与 max_payload_age_secs 对齐，保证集合大小有界
+    if let Some(mut conn) = state.redis.clone() {
+        let key = format!("pam-jwt-issuer:nonce:{}", payload.nonce);
+        let ttl = state.max_payload_age_secs.max(1) as u64;
+        let reserved: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| {
+                error!("redis nonce check failed: {}", e);
+                ApiError::Internal("nonce store")
+            })?;
+        if reserved.is_none() {
+            return Err(ApiError::BadRequest("replayed request"));
+        }
+    }
+
     // 5) PAM 认证
     let mut auth = Authenticator::with_password(&state.pam_service)
         .map_err(|_| ApiError::Internal("pam init"))?;
@@ -324,23 +788,26 @@ async fn issue_token(
         .set_credentials(username, &payload.password);
     auth.authenticate().map_err(|_| ApiError::AuthFailed)?;
 
-    // 6) HS256 颁发 JWT
+    // 6) 颁发 JWT：使用密钥集中最新的未退役密钥（HS256 或 RS256/ES256）
+    let signing_entry = state.active_signing_key()?;
     let exp = now + Duration::minutes(state.jwt_exp_minutes);
     let claims = Claims {
         sun: username.to_string(),
         iat: now.unix_timestamp(),
         exp: exp.unix_timestamp(),
     };
-    let mut header = Header::new(Algorithm::HS256);
-
+    let mut header = Header::new(signing_entry.key.algorithm());
     header.typ = Some("JWT".to_string());
+    if let Some(kid) = signing_entry.key.kid() {
+        header.kid = Some(kid.to_string());
+    }
 
-    let token =
-        encode(&header, &claims, &state.jwt_key).map_err(|_| ApiError::Internal("jwt encode"))?;
+    let token = encode(&header, &claims, signing_entry.key.encoding_key())
+        .map_err(|_| ApiError::Internal("jwt encode"))?;
 
     // 立刻解析头部核对
     let h = decode_header(&token).map_err(|_| ApiError::Internal("decode header"))?;
-    assert_eq!(h.alg, Algorithm::HS256);
+    assert_eq!(h.alg, signing_entry.key.algorithm());
 
     // === 可选签发 OpenSSH 用户证书 ===
     let mut ssh_user_cert_text: Option<String> = None;
@@ -357,7 +824,7 @@ async fn issue_token(
 
         let ca_path = env::var("SSH_CA_KEY_PATH").unwrap_or_else(|_| "/etc/ssh/ssh_user_ca".into());
         let ext_env = env::var("SSH_CERT_EXTENSIONS").unwrap_or_default();
-        let extensions: Vec<&str> = ext_env
+        let mut extensions: Vec<&str> = ext_env
             .split(',')
             .map(|s| s.trim())
             .filter(|s| !s.is_empty())
@@ -367,18 +834,42 @@ async fn issue_token(
             .and_then(|s| s.parse::<u64>().ok())
             .unwrap_or(0);
 
-        match issue_ssh_user_cert(
-            &ca_path,
-            username,
-            now,
-            exp,
-            ssh_pub,
-            &extensions,
+        // 按用户/所属组应用证书策略：额外 principal、扩展、关键选项，以及有效期上限；
+        // 组查询走 NSS（可能是 LDAP/SSSD），放到阻塞线程池避免卡住 Tokio worker
+        let cert_policy = state.cert_policy.clone();
+        let username_owned = username.to_string();
+        let cert_rule = tokio::task::spawn_blocking(move || cert_policy.resolve(&username_owned))
+            .await
+            .map_err(|_| ApiError::Internal("cert policy task"))?;
+        let mut principals = vec![username.to_string()];
+        for p in &cert_rule.principals {
+            if !principals.contains(p) {
+                principals.push(p.clone());
+            }
+        }
+        for e in &cert_rule.extensions {
+            if !extensions.contains(&e.as_str()) {
+                extensions.push(e.as_str());
+            }
+        }
+        let ssh_valid_before = match cert_rule.max_validity_secs {
+            Some(max_secs) => std::cmp::min(exp, now + Duration::seconds(max_secs)),
+            None => exp,
+        };
+
+        match issue_ssh_user_cert(SshCertRequest {
+            ca_key_path: &ca_path,
+            principals: &principals,
+            valid_after: now,
+            valid_before: ssh_valid_before,
+            ssh_pubkey: ssh_pub,
+            extensions: &extensions,
+            critical_options: &cert_rule.critical_options,
             serial_base,
-        ) {
+        }) {
             Ok(cert_txt) => {
                 ssh_user_cert_text = Some(cert_txt);
-                ssh_user_cert_exp = Some(exp.unix_timestamp());
+                ssh_user_cert_exp = Some(ssh_valid_before.unix_timestamp());
             }
             Err(e) => {
                 // 证书失败不影响 JWT；仅记录日志
@@ -398,3 +889,202 @@ async fn issue_token(
         }),
     ))
 }
+
+#[cfg(test)]
+mod cert_option_tests {
+    use super::*;
+    use ssh_key::{Algorithm as SshAlgorithm, Certificate, LineEnding, PrivateKey as SshPrivateKey};
+
+    /// 钉住 fd1e8ca 修复的行为：已知的 `-O` 关键选项名必须落在证书的 critical options
+    /// 里（sshd 才会真正强制执行），其余一律落在普通 extensions 里
+    #[test]
+    fn well_known_critical_options_are_enforced_not_just_advertised() {
+        let mut rng = OsRng;
+        let ca_key = SshPrivateKey::random(&mut rng, SshAlgorithm::Ed25519).unwrap();
+        let ca_path = std::env::temp_dir().join(format!(
+            "pam-jwt-issuer-test-ca-{}-{}",
+            std::process::id(),
+            derive_kid(ca_key.public_key().to_bytes().unwrap().as_slice())
+        ));
+        fs::write(&ca_path, ca_key.to_openssh(LineEnding::LF).unwrap().as_bytes()).unwrap();
+
+        let user_key = SshPrivateKey::random(&mut rng, SshAlgorithm::Ed25519).unwrap();
+        let user_pub = user_key.public_key().to_openssh().unwrap();
+
+        let now = OffsetDateTime::now_utc();
+        let principals = vec!["alice".to_string()];
+        let critical_options = BTreeMap::new();
+        let extensions = [
+            "force-command=/usr/bin/backup-agent",
+            "source-address=10.0.0.0/8",
+            "verify-required",
+            "permit-pty",
+            "no-x11-forwarding",
+        ];
+
+        let cert_text = issue_ssh_user_cert(SshCertRequest {
+            ca_key_path: ca_path.to_str().unwrap(),
+            principals: &principals,
+            valid_after: now,
+            valid_before: now + Duration::hours(1),
+            ssh_pubkey: &user_pub,
+            extensions: &extensions,
+            critical_options: &critical_options,
+            serial_base: 0,
+        })
+        .expect("cert issuance should succeed");
+
+        let _ = fs::remove_file(&ca_path);
+
+        let cert = Certificate::from_openssh(&cert_text).unwrap();
+
+        assert_eq!(
+            cert.critical_options().get("force-command").map(|s| s.as_str()),
+            Some("/usr/bin/backup-agent")
+        );
+        assert_eq!(
+            cert.critical_options().get("source-address").map(|s| s.as_str()),
+            Some("10.0.0.0/8")
+        );
+        assert!(cert.critical_options().contains_key("verify-required"));
+
+        assert!(cert.extensions().contains_key("permit-pty"));
+        assert!(cert.extensions().contains_key("no-x11-forwarding"));
+
+        assert!(!cert.extensions().contains_key("force-command"));
+        assert!(!cert.extensions().contains_key("source-address"));
+        assert!(!cert.extensions().contains_key("verify-required"));
+    }
+}
+
+#[cfg(test)]
+mod key_selection_tests {
+    use super::*;
+
+    fn hmac_entry(id: &str, created_at: i64, not_after: Option<i64>) -> SigningKeyEntry {
+        SigningKeyEntry {
+            id: id.to_string(),
+            key: SigningKey::Hmac(EncodingKey::from_secret(id.as_bytes())),
+            not_after,
+            created_at,
+        }
+    }
+
+    fn state_with(signing_keys: Vec<SigningKeyEntry>) -> AppState {
+        AppState {
+            signing_keys: Arc::new(signing_keys),
+            rsa_keys: Arc::new(Vec::new()),
+            jwt_exp_minutes: 60,
+            pam_service: "test".into(),
+            max_payload_age_secs: 60,
+            redis: None,
+            cert_policy: Arc::new(CertPolicy::default()),
+        }
+    }
+
+    /// 钉住 acddbd1 修复的行为：选中的是 created_at 最大的密钥，而不是清单数组里
+    /// 最后出现的那一条
+    #[test]
+    fn active_signing_key_picks_newest_by_created_at_not_manifest_order() {
+        let mut entries = vec![
+            hmac_entry("newest", 200, None),
+            hmac_entry("older", 100, None),
+        ];
+        entries.sort_by_key(|e| e.created_at);
+
+        let state = state_with(entries);
+        assert_eq!(state.active_signing_key().unwrap().id, "newest");
+    }
+
+    /// 即使退役密钥的 created_at 更大（也就是清单里"更新"），只要已过 not_after
+    /// 就必须跳过，改用仍在有效期内的那一把
+    #[test]
+    fn active_signing_key_skips_retired_keys_even_if_newest() {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let mut entries = vec![
+            hmac_entry("still-active", 100, None),
+            hmac_entry("retired", 200, Some(now - 3600)),
+        ];
+        entries.sort_by_key(|e| e.created_at);
+
+        let state = state_with(entries);
+        assert_eq!(state.active_signing_key().unwrap().id, "still-active");
+    }
+
+    #[test]
+    fn active_signing_key_errors_when_all_retired() {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let entries = vec![hmac_entry("only", 100, Some(now - 1))];
+
+        let state = state_with(entries);
+        assert!(state.active_signing_key().is_err());
+    }
+}
+
+#[cfg(test)]
+mod cert_rule_merge_tests {
+    use super::*;
+
+    #[test]
+    fn merge_unions_principals_and_extensions_without_duplicates() {
+        let mut a = CertRule {
+            principals: vec!["alice".into(), "ops".into()],
+            extensions: vec!["permit-pty".into()],
+            critical_options: BTreeMap::new(),
+            max_validity_secs: None,
+        };
+        let b = CertRule {
+            principals: vec!["ops".into(), "root".into()],
+            extensions: vec!["permit-pty".into(), "permit-agent-forwarding".into()],
+            critical_options: BTreeMap::new(),
+            max_validity_secs: None,
+        };
+
+        a.merge(&b);
+
+        assert_eq!(a.principals, vec!["alice", "ops", "root"]);
+        assert_eq!(a.extensions, vec!["permit-pty", "permit-agent-forwarding"]);
+    }
+
+    #[test]
+    fn merge_lets_later_rule_overwrite_conflicting_critical_options() {
+        let mut a = CertRule {
+            critical_options: BTreeMap::from([("force-command".to_string(), "/bin/a".to_string())]),
+            ..Default::default()
+        };
+        let b = CertRule {
+            critical_options: BTreeMap::from([("force-command".to_string(), "/bin/b".to_string())]),
+            ..Default::default()
+        };
+
+        a.merge(&b);
+
+        assert_eq!(a.critical_options.get("force-command"), Some(&"/bin/b".to_string()));
+    }
+
+    /// 确保是 min()（更严格者生效）而不是误写成 max()——否则一条规则会悄悄
+    /// 放宽证书有效期，而不是收紧它
+    #[test]
+    fn merge_clamps_max_validity_to_the_stricter_of_the_two() {
+        let mut a = CertRule { max_validity_secs: Some(3600), ..Default::default() };
+        let b = CertRule { max_validity_secs: Some(600), ..Default::default() };
+        a.merge(&b);
+        assert_eq!(a.max_validity_secs, Some(600));
+
+        let mut c = CertRule { max_validity_secs: Some(600), ..Default::default() };
+        let d = CertRule { max_validity_secs: Some(3600), ..Default::default() };
+        c.merge(&d);
+        assert_eq!(c.max_validity_secs, Some(600));
+    }
+
+    #[test]
+    fn merge_keeps_the_one_side_that_has_a_cap_when_the_other_is_unset() {
+        let mut a = CertRule { max_validity_secs: Some(600), ..Default::default() };
+        a.merge(&CertRule::default());
+        assert_eq!(a.max_validity_secs, Some(600));
+
+        let mut b = CertRule::default();
+        b.merge(&CertRule { max_validity_secs: Some(600), ..Default::default() });
+        assert_eq!(b.max_validity_secs, Some(600));
+    }
+}